@@ -1,9 +1,14 @@
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, NativeCallContext};
 use std::{
+    cell::RefCell,
     collections::HashMap,
+    fs::{self, File},
     io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
     process::{Child, Command, Stdio},
+    rc::Rc,
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use structopt::StructOpt;
@@ -14,6 +19,15 @@ struct UserInfo {
     username: Option<String>,
     #[structopt(long)]
     password: Option<String>,
+    // Test every exit in parallel (up to max_parallel_exits at a time) and emit
+    // one comparable result per exit, rather than picking a single random exit
+    // per cycle.
+    #[structopt(long)]
+    all_exits: bool,
+    // Pull jobs from the collector over a persistent connection and stream
+    // results back, instead of running the committed config.toml on a timer.
+    #[structopt(long)]
+    collector_pull: bool,
 }
 
 #[derive(Deserialize, Debug)]
@@ -21,13 +35,56 @@ struct Config {
     collector: String,                          // where to send test data
     global_interval: u64, // how many seconds to wait between running all tests
     endpoints: HashMap<String, TestDescriptor>, // what to do in each particular test
+    #[serde(default = "default_base_backoff_sec")]
+    base_backoff_sec: u64, // first retry waits this long, then doubles
+    #[serde(default = "default_max_backoff_sec")]
+    max_backoff_sec: u64, // backoff is capped here before the ±25% jitter is applied
+    #[serde(default = "default_max_attempts")]
+    max_attempts: u32, // give up a flaky step after this many tries (this cycle only)
+    #[serde(default = "default_spool_directory")]
+    spool_directory: String, // results that can't be uploaded are parked here
+    #[serde(default = "default_max_parallel_exits")]
+    max_parallel_exits: u32, // how many exits to test at once in --all-exits mode
+    #[serde(default)]
+    pull_endpoint: Option<String>, // where --collector-pull fetches jobs (defaults to collector)
+}
+
+fn default_base_backoff_sec() -> u64 {
+    2
+}
+fn default_max_backoff_sec() -> u64 {
+    300
+}
+fn default_max_attempts() -> u32 {
+    8
+}
+fn default_spool_directory() -> String {
+    "spool".to_string()
+}
+fn default_max_parallel_exits() -> u32 {
+    4
 }
 
 #[derive(Deserialize, Debug)]
 struct TestDescriptor {
-    url: String,     // what to download
-    iterations: u32, // how many times to download
-    interval: u64,   // how many seconds to wait after each download
+    #[serde(flatten)]
+    kind: TestKind, // what kind of traffic this test generates
+    iterations: u32, // how many samples to collect
+    interval: u64,   // how many seconds to wait after each sample
+}
+
+// The workloads a single endpoint can exercise. Tagged by a `kind` field in the
+// TOML (e.g. `kind = "download"`), so adding a test type is a new variant here.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum TestKind {
+    Download { url: String },
+    Upload { url: String, size_bytes: u64 },
+    Latency { url: String, probes: u32 },
+    ConcurrentDownload { url: String, parallelism: u32 },
+    // An embedded rhai program driving an arbitrary multi-step flow. It names its
+    // own measurements via measure(...), so it bypasses the sample/aggregate path.
+    Script { path: String },
 }
 
 #[derive(Serialize)]
@@ -35,15 +92,48 @@ struct ResultStruct {
     exit: String,
     is_plus: bool,
     time_to_connect: u128,
+    timestamp: u64, // shared wall-clock stamp so --all-exits results line up
     data: HashMap<String, Vec<MeasurementStruct>>,
 }
 
-#[derive(Serialize)]
+// One endpoint's results for a cycle, aggregated over its iteration samples.
+// Percentiles (in milliseconds) summarise the latency distribution far more
+// usefully than a raw dump of every sample, and throughput captures goodput.
+#[derive(Serialize, Clone)]
 struct MeasurementStruct {
-    download_time: u128,
+    p50: u128,
+    p90: u128,
+    p99: u128,
+    min: u128,
+    max: u128,
+    throughput_bytes_per_sec: f64,
     timestamp: u64,
 }
 
+// A single timed observation: how long the operation took and how many bytes it
+// moved (zero for latency probes, which don't transfer a meaningful payload).
+struct Sample {
+    duration: Duration,
+    bytes: u64,
+}
+
+// A job handed out by the collector in --collector-pull mode. The endpoints use
+// the same schema as Config.endpoints, plus a job_id the results are tagged with.
+#[derive(Deserialize, Debug)]
+struct RequestedJob {
+    job_id: String,
+    endpoints: HashMap<String, TestDescriptor>,
+}
+
+// One measurement streamed back to the collector as it's produced, tagged so the
+// controller can attribute it to the job and endpoint that generated it.
+#[derive(Serialize)]
+struct JobStatus<'a> {
+    job_id: &'a str,
+    name: &'a str,
+    measurement: &'a MeasurementStruct,
+}
+
 fn prompt_to_input(prompt: &str) -> String {
     let stdin = io::stdin();
     let mut ret = String::new();
@@ -76,64 +166,107 @@ fn main() -> anyhow::Result<()> {
         .password
         .unwrap_or_else(|| prompt_to_input("Enter your password: "));
 
+    let all_exits = userinfo.all_exits;
+
     std::env::set_var("GEPH_RECURSIVE", "1");
 
+    // Pull mode has its own driver loop: the collector, not a committed TOML,
+    // decides what each cycle does. We still need a Config for the collector URL
+    // and tunables, so fetch it once through a throwaway bootstrap tunnel.
+    if userinfo.collector_pull {
+        let config = {
+            let (mut child, _exit, _is_plus) =
+                connect_to_geph(username.clone(), password.clone());
+            let fetched = with_retry(&RetryPolicy::defaults(), "config fetch", || {
+                run("curl --fail --proxy socks5h://localhost:10909 https://raw.githubusercontent.com/cheekyelf/geph-autotest/main/config.toml")
+                    .context("could not get config file")
+            })
+            .and_then(|bytes| {
+                toml::from_slice::<Config>(&bytes).context("cannot parse TOML file")
+            });
+            // Drop the bootstrap tunnel before the pull loop reuses port 10909.
+            let _ = child.kill();
+            let _ = child.wait();
+            fetched?
+        };
+        return run_collector_pull(&username, &password, &config);
+    }
+
     loop {
-        // Connect to Geph & log exit chosen & time taken to connect
+        // One wall-clock stamp for the whole cycle, so --all-exits results are
+        // directly comparable.
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        // Establish a bootstrap connection on the default ports; we need a proxy
+        // just to fetch the config, and in single-exit mode it also runs the
+        // battery. Killed via defer! so it's reaped even on panic.
         let start = Instant::now();
         let (mut child, exit, is_plus) = connect_to_geph(username.clone(), password.clone());
         scopeguard::defer!({
             let pid = child.id();
-            child.kill().unwrap();
-            child.wait().unwrap();
+            let _ = child.kill();
+            let _ = child.wait();
             eprintln!("KILLLLLED!!!!! pid = {}", pid);
         });
         let time_to_connect = start.elapsed().as_millis();
 
-        let mut result_struct = ResultStruct {
-            exit,
-            is_plus,
-            time_to_connect,
-            data: HashMap::new(),
+        // Fetch testing configuration document into a hashmap. The fetch rides
+        // the freshly-established proxy, so a transient blip shouldn't abort the
+        // whole cycle — retry it with the default backoff policy.
+        let config_file = match with_retry(&RetryPolicy::defaults(), "config fetch", || {
+            run("curl --fail --proxy socks5h://localhost:10909 https://raw.githubusercontent.com/cheekyelf/geph-autotest/main/config.toml")
+                .context("could not get config file")
+        }) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                eprintln!("skipping cycle: {:#}", err);
+                continue;
+            }
         };
-
-        // Fetch testing configuration document into a hashmap
-        let config_file =
-            run("curl --proxy socks5h://localhost:10909 https://raw.githubusercontent.com/cheekyelf/geph-autotest/main/config.toml")
-                .context("could not get config file")?;
         let config: Config = toml::from_slice(&config_file).context("cannot parse TOML file")?;
+        let retry = RetryPolicy::from_config(&config);
+
+        // Before measuring anything new, flush any results that a previous cycle
+        // couldn't hand off to the collector.
+        drain_spool(&config, &retry);
+
+        // Run the battery and collect one result per exit (just one in the
+        // default single-exit mode).
+        let results = if all_exits {
+            run_all_exits(&username, &password, &config, timestamp)
+        } else {
+            vec![ResultStruct {
+                exit,
+                is_plus,
+                time_to_connect,
+                timestamp,
+                data: run_battery(&config.endpoints, 10909),
+            }]
+        };
 
-        // Perform each test
-        for (name, td) in config.endpoints.into_iter() {
-            let mut result_vec: Vec<MeasurementStruct> = Vec::new();
-
-            for _ in 0..=td.iterations {
-                let duration = measure_time(|| {
-                    run(&format!(
-                        "curl --proxy socks5h://localhost:10909 {}> /dev/null",
-                        td.url
-                    ))
-                })
-                .context("could not measure download time")?;
-                // Question: if run() fails, would "could not measure download time" be displayed in the logs too?
-                result_vec.push(MeasurementStruct {
-                    download_time: duration.as_millis(),
-                    timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
-                });
-
-                // Wait a random number of seconds that averages to avg_indi
-                std::thread::sleep(Duration::from_secs(fastrand::u64(0..=(td.interval * 2))));
+        // Send results to the data aggregation server.
+        for result_struct in &results {
+            let json_str = serde_json::to_string(result_struct)
+                .context("could not serialize result_struct")?;
+
+            // A single network blip on the upload used to kill the whole process;
+            // now it's just a retried, self-healing step.
+            if let Err(err) = with_retry(&retry, "collector upload", || {
+                ureq::post(&config.collector)
+                    .send_string(&json_str)
+                    .context("could not upload result to collector")?;
+                Ok(())
+            }) {
+                // Don't throw the measurement away — spool it so the next cycle
+                // (or a later run) can deliver it once the collector is reachable.
+                eprintln!("collector unreachable, spooling result: {:#}", err);
+                if let Err(err) = spool_result(&config.spool_directory, &json_str) {
+                    eprintln!("could not spool result, losing it: {:#}", err);
+                }
             }
-            result_struct.data.insert(name, result_vec);
+            // writeln!(results_file, "{}", json_str).context("could not write result to file")?;
         }
 
-        // Send result to data aggregation server
-        let json_str =
-            serde_json::to_string(&result_struct).context("could not serialize result_struct")?;
-
-        ureq::post(&config.collector).send_string(&json_str)?;
-        // writeln!(results_file, "{}", json_str).context("could not write result to file")?;
-
         // Wait a random number of seconds that averages to avg_total then re-test
         std::thread::sleep(Duration::from_secs(fastrand::u64(
             0..=(config.global_interval * 2),
@@ -141,21 +274,16 @@ fn main() -> anyhow::Result<()> {
     }
 }
 
-// Connects to Geph and returns when connection is established
-fn connect_to_geph(username: String, password: String) -> (Child, String, bool) {
+// Runs `geph4-client sync` and returns whether the user is a plus subscriber
+// together with the list of exit hostnames available to them.
+fn geph_sync(username: &str, password: &str) -> (bool, Vec<String>) {
     // Retrieve a list of all geph exits
     let output = Command::new("geph4-client")
         .arg("sync")
         .arg("--username")
-        .arg(username.clone())
+        .arg(username)
         .arg("--password")
-        .arg(password.clone())
-        // .arg("--http-listen")
-        // .arg("10910")
-        // .arg("--socks5-listen")
-        // .arg("10909")
-        // .arg("--stats-listen")
-        // .arg("10809")
+        .arg(password)
         .stdout(Stdio::piped())
         .spawn()
         .expect("spawning geph4-client failed");
@@ -172,12 +300,9 @@ fn connect_to_geph(username: String, password: String) -> (Child, String, bool)
     struct SubscriptionInfo {
         subscription: Option<serde_json::Value>,
     }
-    let mut is_plus = false;
     let subscription_info: SubscriptionInfo =
         serde_json::from_value(deserialized[0].clone()).expect("could not deserialize user info");
-    if subscription_info.subscription.is_some() {
-        is_plus = true;
-    }
+    let is_plus = subscription_info.subscription.is_some();
 
     #[derive(Deserialize, Debug, Clone)]
     struct ExitDescriptor {
@@ -187,15 +312,36 @@ fn connect_to_geph(username: String, password: String) -> (Child, String, bool)
         serde_json::from_value(deserialized[if is_plus { 1 } else { 2 }].clone())
             .expect("could not deserialize bridges");
 
-    // Randomly pick an exit
-    let exit = exit_list[fastrand::usize(..exit_list.len())].clone();
+    (
+        is_plus,
+        exit_list.into_iter().map(|e| e.hostname).collect(),
+    )
+}
+
+// Spawns a geph4-client tunnel to `exit` on the given local ports and returns it
+// once the tunnel's main loop is up. Ports are parameterized so --all-exits mode
+// can run several tunnels side by side without collisions.
+fn connect_exit(
+    username: &str,
+    password: &str,
+    exit: &str,
+    socks_port: u16,
+    http_port: u16,
+    stats_port: u16,
+) -> Child {
+    // Connecting has no Config yet, so it rides the default backoff policy — the
+    // same ladder the config fetch uses. A tunnel that dies before its main
+    // loop backs off (exponential + jitter, capped) instead of busy-respawning a
+    // hard-down exit with zero delay.
+    let retry = RetryPolicy::defaults();
+    let mut attempt: u32 = 0;
     loop {
         // Connect to Geph with our exit
         let mut child = Command::new("sh")
         .arg("-c")
         .arg(&format!(
-            "geph4-client connect --username {} --password {} --exit-server {} --http-listen 127.0.0.1:10910 --socks5-listen 127.0.0.1:10909 --stats-listen 127.0.0.1:10809",
-            username, password, exit.hostname
+            "geph4-client connect --username {} --password {} --exit-server {} --http-listen 127.0.0.1:{} --socks5-listen 127.0.0.1:{} --stats-listen 127.0.0.1:{}",
+            username, password, exit, http_port, socks_port, stats_port
         ))
         .stderr(Stdio::piped())
         .spawn()
@@ -210,15 +356,413 @@ fn connect_to_geph(username: String, password: String) -> (Child, String, bool)
                 .read_line(&mut line)
                 .expect("could not read from child stderr");
             if n == 0 {
-                eprintln!("OH NO RETRYING!!!!!!");
-                // child.kill().unwrap();
                 child.wait().unwrap();
                 break;
             }
             dbg!(&line);
             if line.contains("TUNNEL_MANAGER MAIN LOOP") {
                 std::thread::spawn(move || std::io::copy(&mut stderr, &mut std::io::sink()));
-                return (child, exit.hostname, is_plus);
+                return child;
+            }
+        }
+
+        attempt += 1;
+        let backoff = retry.backoff_duration(attempt);
+        eprintln!(
+            "geph tunnel to {} exited before its main loop (attempt {}) — retrying in {:?}",
+            exit, attempt, backoff
+        );
+        std::thread::sleep(backoff);
+    }
+}
+
+// Connects to Geph on the default ports using a randomly picked exit.
+fn connect_to_geph(username: String, password: String) -> (Child, String, bool) {
+    let (is_plus, exits) = geph_sync(&username, &password);
+    let exit = exits[fastrand::usize(..exits.len())].clone();
+    let child = connect_exit(&username, &password, &exit, 10909, 10910, 10809);
+    (child, exit, is_plus)
+}
+
+// Runs the full endpoint battery against the proxy on `socks_port`, returning the
+// populated result data map. Shared by single-exit and --all-exits modes.
+fn run_battery(
+    endpoints: &HashMap<String, TestDescriptor>,
+    socks_port: u16,
+) -> HashMap<String, Vec<MeasurementStruct>> {
+    let mut data: HashMap<String, Vec<MeasurementStruct>> = HashMap::new();
+    for (name, td) in endpoints {
+        data.extend(run_endpoint(name, td, socks_port));
+    }
+    data
+}
+
+// Runs a single endpoint and returns the named measurements it produced. A
+// script may contribute several entries; the other kinds contribute one.
+fn run_endpoint(
+    name: &str,
+    td: &TestDescriptor,
+    socks_port: u16,
+) -> HashMap<String, Vec<MeasurementStruct>> {
+    let mut data: HashMap<String, Vec<MeasurementStruct>> = HashMap::new();
+
+    // Scripted flows record their own named measurements, so they merge straight
+    // into the result map rather than going through the sampling loop below.
+    if let TestKind::Script { path } = &td.kind {
+        match run_script(path, socks_port) {
+            Ok(entries) => data.extend(entries),
+            Err(err) => eprintln!("script test {} failed: {:#}", name, err),
+        }
+        return data;
+    }
+
+    let mut samples: Vec<Sample> = Vec::new();
+    for _ in 0..td.iterations {
+        match run_test(&td.kind, socks_port) {
+            Ok(sample) => samples.push(sample),
+            // A single failed sample shouldn't drop the endpoint entirely;
+            // just skip it and keep measuring.
+            Err(err) => eprintln!("test {} sample failed: {:#}", name, err),
+        }
+
+        // Wait a random number of seconds that averages to avg_indi
+        std::thread::sleep(Duration::from_secs(fastrand::u64(0..=(td.interval * 2))));
+    }
+
+    if !samples.is_empty() {
+        match aggregate(&samples) {
+            Ok(measurement) => {
+                data.insert(name.to_string(), vec![measurement]);
+            }
+            Err(err) => eprintln!("could not aggregate {}: {:#}", name, err),
+        }
+    }
+
+    data
+}
+
+// Connects to every available exit (bounded by max_parallel_exits) and runs the
+// full battery against each in parallel, tagging every ResultStruct with one
+// shared wall-clock timestamp so the exits can be compared under identical
+// network conditions.
+fn run_all_exits(
+    username: &str,
+    password: &str,
+    config: &Config,
+    timestamp: u64,
+) -> Vec<ResultStruct> {
+    let (is_plus, exits) = geph_sync(username, password);
+    let max_parallel = config.max_parallel_exits.max(1) as usize;
+
+    let mut results = Vec::new();
+    // Bound concurrency by processing the exits in batches; each worker gets its
+    // own port block so the tunnels don't fight over listen ports.
+    for batch in exits.chunks(max_parallel) {
+        let batch_results: Vec<ResultStruct> = std::thread::scope(|scope| {
+            let handles: Vec<_> = batch
+                .iter()
+                .enumerate()
+                .map(|(i, exit)| {
+                    // Start above the bootstrap connection's 10909 so the tunnel
+                    // we used to fetch the config doesn't clash with a worker.
+                    let socks_port = 11000 + (i as u16) * 10;
+                    scope.spawn(move || {
+                        test_single_exit(
+                            username, password, exit, is_plus, socks_port, config, timestamp,
+                        )
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .filter_map(|h| match h.join() {
+                    Ok(result) => Some(result),
+                    Err(_) => {
+                        eprintln!("an exit worker panicked; skipping it");
+                        None
+                    }
+                })
+                .collect()
+        });
+        results.extend(batch_results);
+    }
+
+    results
+}
+
+// Connects to one exit on its own port block, runs the battery, and returns the
+// tagged result. The scopeguard::defer! guarantees the geph child is reaped even
+// if the battery panics partway through.
+fn test_single_exit(
+    username: &str,
+    password: &str,
+    exit: &str,
+    is_plus: bool,
+    socks_port: u16,
+    config: &Config,
+    timestamp: u64,
+) -> ResultStruct {
+    let start = Instant::now();
+    let mut child = connect_exit(
+        username,
+        password,
+        exit,
+        socks_port,
+        socks_port + 1,
+        socks_port + 2,
+    );
+    scopeguard::defer! {
+        let pid = child.id();
+        let _ = child.kill();
+        let _ = child.wait();
+        eprintln!("KILLLLLED!!!!! pid = {}", pid);
+    }
+    let time_to_connect = start.elapsed().as_millis();
+
+    ResultStruct {
+        exit: exit.to_string(),
+        is_plus,
+        time_to_connect,
+        timestamp,
+        data: run_battery(&config.endpoints, socks_port),
+    }
+}
+
+// Pull mode: instead of running the committed config.toml on a timer, keep a
+// long-lived connection to the collector, pull one job at a time, run it, and
+// stream each measurement back as it lands, tagged with the job_id. This lets a
+// central controller steer the whole fleet without editing a committed file.
+fn run_collector_pull(username: &str, password: &str, config: &Config) -> anyhow::Result<()> {
+    // A single reused agent gives us HTTP keep-alive across job fetches and
+    // status posts, modelling build-o-tron's persistent RunnerClient connection.
+    let agent = ureq::AgentBuilder::new().build();
+    let retry = RetryPolicy::from_config(config);
+    let pull_endpoint = config.pull_endpoint.as_deref().unwrap_or(&config.collector);
+
+    loop {
+        // Flush anything a previous poll spooled when the collector was down,
+        // mirroring the main loop so no status is lost on the pull path either.
+        drain_spool(config, &retry);
+
+        // Ask for the next job. No job (or a blip) just means wait and retry.
+        let job: RequestedJob = match agent
+            .get(pull_endpoint)
+            .call()
+            .context("could not fetch job")
+            .and_then(|resp| resp.into_string().context("could not read job body"))
+            .and_then(|body| serde_json::from_str(&body).context("could not parse job"))
+        {
+            Ok(job) => job,
+            Err(err) => {
+                eprintln!("no job available: {:#}", err);
+                std::thread::sleep(backoff_sleep(&retry));
+                continue;
+            }
+        };
+        eprintln!("running job {}", job.job_id);
+
+        // Stand up a tunnel on the default ports for the duration of this job.
+        let (mut child, _exit, _is_plus) =
+            connect_to_geph(username.to_string(), password.to_string());
+        scopeguard::defer!({
+            let _ = child.kill();
+            let _ = child.wait();
+        });
+
+        for (name, td) in &job.endpoints {
+            for (name, measurements) in run_endpoint(name, td, 10909) {
+                for measurement in &measurements {
+                    stream_status(&agent, &retry, config, &job.job_id, &name, measurement);
+                }
+            }
+        }
+    }
+}
+
+// Streams a single measurement back to the collector, spooling it (tagged with a
+// JobStatus envelope) if the collector is unreachable so nothing is lost.
+fn stream_status(
+    agent: &ureq::Agent,
+    retry: &RetryPolicy,
+    config: &Config,
+    job_id: &str,
+    name: &str,
+    measurement: &MeasurementStruct,
+) {
+    let status = JobStatus {
+        job_id,
+        name,
+        measurement,
+    };
+    let json_str = match serde_json::to_string(&status) {
+        Ok(json_str) => json_str,
+        Err(err) => {
+            eprintln!("could not serialize status: {:#}", err);
+            return;
+        }
+    };
+
+    if let Err(err) = with_retry(retry, "status stream", || {
+        agent
+            .post(&config.collector)
+            .send_string(&json_str)
+            .context("could not stream status to collector")?;
+        Ok(())
+    }) {
+        eprintln!("collector unreachable, spooling status: {:#}", err);
+        if let Err(err) = spool_result(&config.spool_directory, &json_str) {
+            eprintln!("could not spool status, losing it: {:#}", err);
+        }
+    }
+}
+
+// A one-shot backoff sleep used while idling between job polls.
+fn backoff_sleep(retry: &RetryPolicy) -> Duration {
+    retry.backoff_duration(1)
+}
+
+// Retries a fallible step with exponential backoff and jitter. The attempt
+// counter resets to zero on every fresh call, so a step that fails and gives up
+// only fails the current loop iteration — never the whole tester. After the nth
+// failure we sleep base_backoff_sec * 2^(n-1), capped at max_backoff_sec, perturbed by
+// ±25% random jitter so a fleet of testers doesn't hammer an exit in lockstep.
+fn with_retry<T>(
+    policy: &RetryPolicy,
+    what: &str,
+    mut step: impl FnMut() -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let mut attempt: u32 = 0;
+    loop {
+        match step() {
+            Ok(value) => return Ok(value),
+            Err(err) => {
+                attempt += 1;
+                if attempt >= policy.max_attempts {
+                    return Err(err.context(format!(
+                        "{} still failing after {} attempts, giving up this cycle",
+                        what, attempt
+                    )));
+                }
+                let backoff = policy.backoff_duration(attempt);
+                eprintln!(
+                    "{} failed (attempt {}/{}): {:#} — retrying in {:?}",
+                    what, attempt, policy.max_attempts, err, backoff
+                );
+                std::thread::sleep(backoff);
+            }
+        }
+    }
+}
+
+// Tunables governing with_retry's backoff curve, borrowed from Config once it's
+// fetched (and falling back to the serde defaults for the pre-config steps).
+struct RetryPolicy {
+    base_backoff_sec: u64,
+    max_backoff_sec: u64,
+    max_attempts: u32,
+}
+
+impl RetryPolicy {
+    // The policy used before we've managed to fetch a Config (connect & fetch).
+    fn defaults() -> Self {
+        RetryPolicy {
+            base_backoff_sec: default_base_backoff_sec(),
+            max_backoff_sec: default_max_backoff_sec(),
+            max_attempts: default_max_attempts(),
+        }
+    }
+
+    fn from_config(config: &Config) -> Self {
+        RetryPolicy {
+            base_backoff_sec: config.base_backoff_sec,
+            max_backoff_sec: config.max_backoff_sec,
+            max_attempts: config.max_attempts,
+        }
+    }
+
+    // The backoff delay after the `attempt`th failure (1-based): base_backoff_sec
+    // doubled for each earlier failure, clamped to max_backoff_sec and jittered
+    // by ±25%.
+    fn backoff_duration(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_backoff_sec
+            .saturating_mul(1u64 << attempt.saturating_sub(1).min(16));
+        let capped = exp.min(self.max_backoff_sec);
+        let jitter = 0.75 + fastrand::f64() * 0.5; // uniformly in [0.75, 1.25)
+        Duration::from_secs_f64(capped as f64 * jitter)
+    }
+}
+
+// Durably parks a serialized result in the spool directory so nothing is lost
+// across a collector outage. We write to `<name>.tmp`, flush it all the way to
+// disk with sync_data(), then rename into place — so a reader draining the spool
+// never observes a half-written file (same pattern as wgconfd's update_file).
+fn spool_result(spool_directory: &str, json_str: &str) -> anyhow::Result<()> {
+    fs::create_dir_all(spool_directory)
+        .with_context(|| format!("could not create spool directory {}", spool_directory))?;
+
+    // Timestamp keeps spooled files draining roughly oldest-first; the random
+    // suffix keeps two results in the same millisecond from colliding.
+    let name = format!(
+        "result_{}_{:08x}.json",
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis(),
+        fastrand::u32(..)
+    );
+    let final_path = Path::new(spool_directory).join(&name);
+    let tmp_path = final_path.with_extension("json.tmp");
+
+    let mut file = File::create(&tmp_path)
+        .with_context(|| format!("could not create spool temp file {:?}", tmp_path))?;
+    file.write_all(json_str.as_bytes())
+        .context("could not write spooled result")?;
+    file.sync_data().context("could not sync spooled result")?;
+    fs::rename(&tmp_path, &final_path)
+        .with_context(|| format!("could not rename {:?} into place", tmp_path))?;
+    Ok(())
+}
+
+// Drains previously-spooled results to the collector before running new tests.
+// Each file that uploads cleanly is deleted; on the first hard failure we stop
+// and leave the rest in place for the next cycle. A single attempt per file (no
+// with_retry ladder) keeps a sustained collector outage — the exact scenario the
+// spool exists for — from stalling new tests for tens of minutes per file while
+// the whole spool is re-drained next cycle anyway. Timestamped filenames are
+// sorted so files drain oldest-first; leftover `.tmp` files from an interrupted
+// write are ignored by the filter on the `.json` suffix.
+fn drain_spool(config: &Config, _retry: &RetryPolicy) {
+    let entries = match fs::read_dir(&config.spool_directory) {
+        Ok(entries) => entries,
+        Err(_) => return, // nothing spooled yet
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().and_then(|e| e.to_str()) == Some("json"))
+        .collect();
+    paths.sort();
+    for path in paths {
+        let json_str = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                eprintln!("could not read spooled {:?}: {:#}", path, err);
+                continue;
+            }
+        };
+        match ureq::post(&config.collector)
+            .send_string(&json_str)
+            .context("could not upload spooled result")
+        {
+            Ok(_) => {
+                if let Err(err) = fs::remove_file(&path) {
+                    eprintln!("uploaded but could not remove {:?}: {:#}", path, err);
+                }
+            }
+            Err(err) => {
+                // Collector still down; leave this and every later file spooled
+                // and retry the whole batch next cycle rather than stalling here.
+                eprintln!("leaving {:?} spooled for next cycle: {:#}", path, err);
+                break;
             }
         }
     }
@@ -230,18 +774,208 @@ fn run(command: &str) -> anyhow::Result<Vec<u8>> {
         .arg("-c")
         .arg(command)
         .stdout(Stdio::piped())
-        // .stderr(Stdio::null())
+        .stderr(Stdio::piped())
         .spawn()?;
     eprintln!("running command {}", command);
     let output = child.wait_with_output()?;
 
+    if !output.status.success() {
+        anyhow::bail!(
+            "command {} failed with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
     return Ok(output.stdout);
 }
 
-fn measure_time(
-    f: impl FnOnce() -> Result<Vec<u8>, anyhow::Error>,
-) -> Result<Duration, anyhow::Error> {
-    let start = Instant::now();
-    f().context("could not download test file")?;
-    Ok(start.elapsed())
+// Runs one sample of a test kind, routed through the SOCKS5 proxy on `socks_port`.
+fn run_test(kind: &TestKind, socks_port: u16) -> anyhow::Result<Sample> {
+    match kind {
+        // Fetch the body (not to /dev/null) so we can size it for throughput.
+        TestKind::Download { url } => {
+            let start = Instant::now();
+            let body = run(&format!(
+                "curl --fail --proxy socks5h://localhost:{} {}",
+                socks_port, url
+            ))?;
+            Ok(Sample {
+                duration: start.elapsed(),
+                bytes: body.len() as u64,
+            })
+        }
+        // Push `size_bytes` of zeros up through the proxy.
+        TestKind::Upload { url, size_bytes } => {
+            let start = Instant::now();
+            run(&format!(
+                "head -c {} /dev/zero | curl --fail --proxy socks5h://localhost:{} --data-binary @- {}",
+                size_bytes, socks_port, url
+            ))?;
+            Ok(Sample {
+                duration: start.elapsed(),
+                bytes: *size_bytes,
+            })
+        }
+        // Average a handful of tiny HEAD probes; payload bytes aren't meaningful.
+        TestKind::Latency { url, probes } => {
+            let probes = (*probes).max(1);
+            let start = Instant::now();
+            for _ in 0..probes {
+                run(&format!(
+                    "curl --fail --proxy socks5h://localhost:{} -s -o /dev/null -I {}",
+                    socks_port, url
+                ))?;
+            }
+            Ok(Sample {
+                duration: start.elapsed() / probes,
+                bytes: 0,
+            })
+        }
+        // Fire `parallelism` downloads at once and time until the slowest lands.
+        TestKind::ConcurrentDownload { url, parallelism } => {
+            let parallelism = (*parallelism).max(1);
+            let start = Instant::now();
+            let handles: Vec<_> = (0..parallelism)
+                .map(|_| {
+                    let url = url.clone();
+                    std::thread::spawn(move || {
+                        run(&format!(
+                            "curl --fail --proxy socks5h://localhost:{} {}",
+                            socks_port, url
+                        ))
+                    })
+                })
+                .collect();
+            let mut bytes = 0u64;
+            for handle in handles {
+                let body = handle
+                    .join()
+                    .map_err(|_| anyhow::anyhow!("concurrent download thread panicked"))??;
+                bytes += body.len() as u64;
+            }
+            Ok(Sample {
+                duration: start.elapsed(),
+                bytes,
+            })
+        }
+        // Scripts are driven by run_script, not the sample/aggregate path; the
+        // battery intercepts them before they ever reach here.
+        TestKind::Script { .. } => {
+            anyhow::bail!("script test kinds must be run via run_script")
+        }
+    }
+}
+
+// Executes an embedded rhai script and returns the named measurements it
+// recorded via measure(...). The http_get/http_post host functions tunnel
+// through the SOCKS5 proxy on localhost:`socks_port`, the same one run_test uses,
+// so scripted flows see exactly the same path as the built-in test kinds.
+fn run_script(
+    path: &str,
+    socks_port: u16,
+) -> anyhow::Result<HashMap<String, Vec<MeasurementStruct>>> {
+    // Scripts push their timed results in here via measure(name, fn).
+    let measurements: Rc<RefCell<HashMap<String, Vec<MeasurementStruct>>>> =
+        Rc::new(RefCell::new(HashMap::new()));
+
+    let mut engine = Engine::new();
+
+    engine.register_fn(
+        "http_get",
+        move |url: &str| -> Result<String, Box<EvalAltResult>> {
+            let body = run(&format!(
+                "curl --fail --proxy socks5h://localhost:{} {}",
+                socks_port,
+                shell_single_quote(url)
+            ))
+            .map_err(|e| e.to_string())?;
+            Ok(String::from_utf8_lossy(&body).into_owned())
+        },
+    );
+    engine.register_fn(
+        "http_post",
+        move |url: &str, body: &str| -> Result<String, Box<EvalAltResult>> {
+            let out = run(&format!(
+                "curl --fail --proxy socks5h://localhost:{} --data-binary {} {}",
+                socks_port,
+                shell_single_quote(body),
+                shell_single_quote(url)
+            ))
+            .map_err(|e| e.to_string())?;
+            Ok(String::from_utf8_lossy(&out).into_owned())
+        },
+    );
+    engine.register_fn("sleep", |secs: i64| {
+        std::thread::sleep(Duration::from_secs(secs.max(0) as u64));
+    });
+
+    // measure("name", || { ... }) times the closure and records it under "name".
+    let sink = measurements.clone();
+    engine.register_fn(
+        "measure",
+        move |ctx: NativeCallContext, name: &str, body: FnPtr| -> Result<(), Box<EvalAltResult>> {
+            let start = Instant::now();
+            let _: Dynamic = body.call_within_context(&ctx, ())?;
+            let sample = Sample {
+                duration: start.elapsed(),
+                bytes: 0,
+            };
+            let measurement = aggregate(&[sample]).map_err(|e| e.to_string())?;
+            sink.borrow_mut()
+                .entry(name.to_string())
+                .or_default()
+                .push(measurement);
+            Ok(())
+        },
+    );
+
+    let program =
+        fs::read_to_string(path).with_context(|| format!("could not read script {}", path))?;
+    engine
+        .run(&program)
+        .map_err(|e| anyhow::anyhow!("script {} failed: {}", path, e))?;
+
+    let recorded = measurements.borrow().clone();
+    Ok(recorded)
+}
+
+// Wraps a string in single quotes for safe interpolation into an `sh -c` command.
+fn shell_single_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// Collapses a batch of samples into the percentile/throughput summary we report.
+fn aggregate(samples: &[Sample]) -> anyhow::Result<MeasurementStruct> {
+    let mut millis: Vec<u128> = samples.iter().map(|s| s.duration.as_millis()).collect();
+    millis.sort_unstable();
+
+    let total_bytes: u64 = samples.iter().map(|s| s.bytes).sum();
+    let total_secs: f64 = samples.iter().map(|s| s.duration.as_secs_f64()).sum();
+    let throughput_bytes_per_sec = if total_secs > 0.0 {
+        total_bytes as f64 / total_secs
+    } else {
+        0.0
+    };
+
+    Ok(MeasurementStruct {
+        p50: percentile(&millis, 50),
+        p90: percentile(&millis, 90),
+        p99: percentile(&millis, 99),
+        min: *millis.first().unwrap_or(&0),
+        max: *millis.last().unwrap_or(&0),
+        throughput_bytes_per_sec,
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    })
+}
+
+// Nearest-rank percentile over an already-sorted slice of millisecond samples.
+fn percentile(sorted: &[u128], p: usize) -> u128 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    // 1-based rank ceil(p/100 * n), kept inside 1..=n.
+    let rank = (p * sorted.len() + 99) / 100;
+    sorted[rank.clamp(1, sorted.len()) - 1]
 }